@@ -20,6 +20,11 @@ fn main() {
             .atleast_version("4.0")
             .probe("libswscale")
             .unwrap(),
+        #[cfg(feature = "swresample")]
+        pkg_config::Config::new()
+            .atleast_version("3.0")
+            .probe("libswresample")
+            .unwrap(),
     ];
     let mut wrapper = cc::Build::new();
 
@@ -35,6 +40,9 @@ fn main() {
     if cfg!(feature = "swscale") {
         wrapper.define("MOONFIRE_USE_SWSCALE", Some("1"));
     }
+    if cfg!(feature = "swresample") {
+        wrapper.define("MOONFIRE_USE_SWRESAMPLE", Some("1"));
+    }
 
     wrapper.file("src/wrapper.c").compile("libwrapper.a");
 }
@@ -5,7 +5,7 @@ use crate::avutil::{
     moonfire_ffmpeg_frame_stuff, AVFrame, Dictionary, Error, ImageDimensions, MediaType,
     PixelFormat, Rational, VideoFrame,
 };
-use std::cell::Ref;
+use std::cell::{Ref, RefCell};
 use std::ptr;
 
 //#[link(name = "avcodec")]
@@ -13,12 +13,10 @@ extern "C" {
     pub(crate) fn avcodec_version() -> libc::c_int;
     pub(crate) fn avcodec_configuration() -> *mut libc::c_char;
     fn avcodec_alloc_context3(codec: *const AVCodec) -> *mut AVCodecContext;
-    fn avcodec_decode_video2(
-        ctx: *const AVCodecContext,
-        picture: *mut AVFrame,
-        got_picture_ptr: *mut libc::c_int,
-        pkt: *const AVPacket,
-    ) -> libc::c_int;
+    fn avcodec_send_packet(ctx: *mut AVCodecContext, pkt: *const AVPacket) -> libc::c_int;
+    fn avcodec_receive_frame(ctx: *mut AVCodecContext, frame: *mut AVFrame) -> libc::c_int;
+    fn avcodec_send_frame(ctx: *mut AVCodecContext, frame: *const AVFrame) -> libc::c_int;
+    fn avcodec_receive_packet(ctx: *mut AVCodecContext, pkt: *mut AVPacket) -> libc::c_int;
     fn avcodec_get_name(codec_id: libc::c_int) -> *const libc::c_char;
     fn avcodec_find_decoder(codec_id: libc::c_int) -> *const AVCodec;
     fn avcodec_find_encoder(codec_id: libc::c_int) -> *const AVCodec;
@@ -34,6 +32,11 @@ extern "C" {
     ) -> libc::c_int;
     pub(crate) fn av_init_packet(p: *mut AVPacket);
     fn av_packet_unref(p: *mut AVPacket);
+    pub(crate) fn av_packet_rescale_ts(p: *mut AVPacket, from: Rational, to: Rational);
+    pub(crate) fn avcodec_parameters_copy(
+        dst: *mut AVCodecParameters,
+        src: *const AVCodecParameters,
+    ) -> libc::c_int;
 }
 
 //#[link(name = "wrapper")]
@@ -48,6 +51,10 @@ extern "C" {
     fn moonfire_ffmpeg_codecpar_dims(ctx: *const AVCodecParameters) -> ImageDimensions;
     fn moonfire_ffmpeg_codecpar_extradata(ctx: *const AVCodecParameters) -> DataLen;
 
+    /// Reads the `AV_PKT_DATA_DISPLAYMATRIX` side data (if any) and normalizes the result of
+    /// `av_display_rotation_get` to one of 0, 90, 180, or 270 degrees clockwise.
+    fn moonfire_ffmpeg_codecpar_rotation(ctx: *const AVCodecParameters) -> libc::c_int;
+
     fn moonfire_ffmpeg_cctx_codec_id(ctx: *const AVCodecContext) -> CodecId;
     fn moonfire_ffmpeg_cctx_codec_type(ctx: *const AVCodecContext) -> MediaType;
     fn moonfire_ffmpeg_cctx_pix_fmt(ctx: *const AVCodecContext) -> PixelFormat;
@@ -194,6 +201,13 @@ impl AVCodecParameters {
     pub fn codec_type(&self) -> MediaType {
         unsafe { moonfire_ffmpeg_codecpar_codec_type(self) }
     }
+
+    /// Returns the stream's display-matrix rotation in degrees clockwise: one of 0, 90, 180,
+    /// or 270. Cameras and phones record portrait video with this set via stream side data
+    /// rather than by actually rotating the encoded pixels.
+    pub fn rotation(&self) -> i32 {
+        unsafe { moonfire_ffmpeg_codecpar_rotation(self) }
+    }
 }
 
 pub struct InputCodecParameters<'s>(pub(crate) &'s AVCodecParameters);
@@ -291,21 +305,34 @@ impl DecodeContext {
         unsafe { self.ctx.as_ref() }
     }
 
-    pub fn decode_video(&self, pkt: &Packet, frame: &mut VideoFrame) -> Result<bool, Error> {
-        let mut got_picture: libc::c_int = 0;
-        Error::wrap(unsafe {
-            avcodec_decode_video2(
-                self.ctx.as_ptr(),
-                frame.frame.as_mut(),
-                &mut got_picture,
-                *pkt.0,
-            )
-        })?;
-        if got_picture != 0 {
-            unsafe { moonfire_ffmpeg_frame_stuff(frame.frame.as_ptr(), &mut frame.stuff) };
-            return Ok(true);
+    /// Feeds a packet to the decoder. Pass `None` to flush: this signals end-of-stream and
+    /// causes the decoder to emit any frames it had been buffering (e.g. for B-frame reordering).
+    pub fn send_packet(&self, pkt: Option<&Packet>) -> Result<(), Error> {
+        let pkt = match pkt {
+            Some(pkt) => *pkt.0,
+            None => ptr::null(),
         };
-        Ok(false)
+        Error::wrap(unsafe { avcodec_send_packet(self.ctx.as_ptr(), pkt) })?;
+        Ok(())
+    }
+
+    /// Pulls one decoded frame out of the decoder, if one is available.
+    ///
+    /// Returns `Ok(false)` on `AVERROR(EAGAIN)` (more packets must be sent before a frame is
+    /// available) or `AVERROR_EOF` (the decoder has been fully flushed). Callers should call
+    /// this repeatedly after each `send_packet` until it returns `Ok(false)`, since a single
+    /// packet may produce zero, one, or several frames.
+    pub fn receive_frame(&self, frame: &mut VideoFrame) -> Result<bool, Error> {
+        match Error::wrap(unsafe {
+            avcodec_receive_frame(self.ctx.as_ptr(), frame.frame.as_ptr())
+        }) {
+            Ok(_) => {
+                unsafe { moonfire_ffmpeg_frame_stuff(frame.frame.as_ptr(), &mut frame.stuff) };
+                Ok(true)
+            }
+            Err(e) if e.is_again() || e.is_eof() => Ok(false),
+            Err(e) => Err(e),
+        }
     }
 }
 
@@ -313,25 +340,39 @@ impl DecodeContext {
 pub struct Encoder(&'static AVCodec);
 
 impl Encoder {
-    /*pub fn alloc_context(self) -> Result<EncodeContext, Error> {
-        let ctx = unsafe { avcodec_alloc_context3(self.0) };
-        if ctx.is_null() {
+    pub fn alloc_context(self) -> Result<EncodeContext, Error> {
+        let ctx = ptr::NonNull::new(unsafe { avcodec_alloc_context3(self.0) })
+            .ok_or_else(Error::enomem)?;
+        let pkt = unsafe { moonfire_ffmpeg_packet_alloc() };
+        if pkt.is_null() {
+            let mut ctx = ctx.as_ptr();
+            unsafe { avcodec_free_context(&mut ctx) };
             return Err(Error::enomem());
         }
+        unsafe { av_init_packet(pkt) };
         Ok(EncodeContext {
             encoder: self,
             ctx,
+            pkt: RefCell::new(pkt),
         })
-    }*/
+    }
 }
 
-pub struct EncodeContext<'a>(&'a mut AVCodecContext);
+pub struct EncodeContext {
+    encoder: Encoder,
+    ctx: ptr::NonNull<AVCodecContext>,
+    pkt: RefCell<*mut AVPacket>,
+}
 
-/*impl Drop for EncodeContext {
+impl Drop for EncodeContext {
     fn drop(&mut self) {
-        unsafe { avcodec_free_context(&mut self.ctx) }
+        unsafe {
+            moonfire_ffmpeg_packet_free(*self.pkt.borrow());
+            let mut ctx = self.ctx.as_ptr();
+            avcodec_free_context(&mut ctx);
+        }
     }
-}*/
+}
 
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
@@ -343,13 +384,43 @@ pub struct VideoParameters {
     time_base: Rational,
 }
 
-impl<'a> EncodeContext<'a> {
+impl EncodeContext {
+    pub fn ctx(&self) -> &AVCodecContext {
+        unsafe { self.ctx.as_ref() }
+    }
+
     pub fn set_params(&mut self, p: &VideoParameters) {
-        unsafe { moonfire_ffmpeg_cctx_set_params(self.0, p) };
+        unsafe { moonfire_ffmpeg_cctx_set_params(self.ctx.as_ptr(), p) };
     }
 
-    pub fn open(&mut self, encoder: Encoder, options: &mut Dictionary) -> Result<(), Error> {
-        Error::wrap(unsafe { avcodec_open2(self.0, encoder.0, options) })?;
+    pub fn open(&mut self, options: &mut Dictionary) -> Result<(), Error> {
+        Error::wrap(unsafe { avcodec_open2(self.ctx.as_ptr(), self.encoder.0, options) })?;
         Ok(())
     }
+
+    /// Feeds a frame to the encoder. Pass `None` to flush: this signals end-of-stream and
+    /// causes the encoder to emit any packets it had been buffering.
+    pub fn send_frame(&mut self, frame: Option<&VideoFrame>) -> Result<(), Error> {
+        let f = match frame {
+            Some(frame) => frame.frame.as_ptr(),
+            None => ptr::null_mut(),
+        };
+        Error::wrap(unsafe { avcodec_send_frame(self.ctx.as_ptr(), f) })?;
+        Ok(())
+    }
+
+    /// Pulls one encoded packet out of the encoder, if one is available.
+    ///
+    /// Returns `Ok(None)` on `AVERROR(EAGAIN)` (more frames must be sent before a packet is
+    /// available) or `AVERROR_EOF` (the encoder has been fully flushed). Callers should call
+    /// this repeatedly after each `send_frame` until it returns `Ok(None)`, since a single frame
+    /// may produce zero, one, or several packets.
+    pub fn receive_packet(&mut self) -> Result<Option<Packet<'_>>, Error> {
+        let pkt = self.pkt.borrow();
+        match Error::wrap(unsafe { avcodec_receive_packet(self.ctx.as_ptr(), *pkt) }) {
+            Ok(_) => Ok(Some(Packet(pkt))),
+            Err(e) if e.is_again() || e.is_eof() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
 }
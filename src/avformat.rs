@@ -2,10 +2,10 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use crate::avcodec::{
-    av_init_packet, moonfire_ffmpeg_packet_alloc, moonfire_ffmpeg_packet_free, AVCodecParameters,
-    AVPacket, InputCodecParameters, Packet,
+    av_init_packet, av_packet_rescale_ts, avcodec_parameters_copy, moonfire_ffmpeg_packet_alloc,
+    moonfire_ffmpeg_packet_free, AVCodecParameters, AVPacket, InputCodecParameters, Packet,
 };
-use crate::avutil::{Dictionary, Error};
+use crate::avutil::{av_free, Dictionary, Error, MediaType, Rational};
 use std::cell::RefCell;
 use std::convert::TryFrom;
 use std::ffi::CStr;
@@ -48,9 +48,22 @@ extern "C" {
     ) -> *mut AVIOContext;
     fn avio_context_free(s: *mut *mut AVIOContext);
 
-    //fn avformat_alloc_output_context2(ctx: *mut *mut AVFormatContext, oformat: *mut AVOutputFormat,
-    //                                  format_name: *const libc::c_char,
-    //                                  filename: *const libc::c_char) -> libc::c_int;
+    /// Opens an `AVIOContext` that accumulates written bytes in memory instead of hitting a
+    /// filesystem or custom `IoContext`; see `avio_close_dyn_buf`.
+    fn avio_open_dyn_buf(s: *mut *mut AVIOContext) -> libc::c_int;
+
+    /// Flushes and closes an `AVIOContext` opened via `avio_open_dyn_buf`, handing back the
+    /// accumulated bytes in a freshly `av_malloc`'d buffer (free it with `avutil::av_free`) and
+    /// returning its length, or a negative `AVERROR` on failure.
+    fn avio_close_dyn_buf(s: *mut AVIOContext, pbuffer: *mut *mut u8) -> libc::c_int;
+
+    fn avformat_alloc_output_context2(
+        ctx: *mut *mut AVFormatContext,
+        oformat: *const libc::c_void,
+        format_name: *const libc::c_char,
+        filename: *const libc::c_char,
+    ) -> libc::c_int;
+    fn avformat_free_context(ctx: *mut AVFormatContext);
     fn avformat_open_input(
         ctx: *mut *mut AVFormatContext,
         url: *const libc::c_char,
@@ -62,9 +75,26 @@ extern "C" {
         ctx: *mut AVFormatContext,
         options: *mut Dictionary,
     ) -> libc::c_int;
-    //fn avformat_new_stream(s: *mut AVFormatContext, c: *const AVCodec) -> *mut AVStream;
-    //fn avformat_write_header(c: *mut AVFormatContext, opts: *mut *mut AVDictionary) -> libc::c_int;
+    fn avformat_new_stream(s: *mut AVFormatContext, c: *const libc::c_void) -> *mut AVStream;
+    fn avformat_write_header(ctx: *mut AVFormatContext, options: *mut Dictionary) -> libc::c_int;
     fn av_read_frame(ctx: *mut AVFormatContext, p: *mut AVPacket) -> libc::c_int;
+    fn av_find_best_stream(
+        ctx: *mut AVFormatContext,
+        media_type: libc::c_int,
+        wanted_stream_nb: libc::c_int,
+        related_stream: libc::c_int,
+        decoder_ret: *mut *const libc::c_void,
+        flags: libc::c_int,
+    ) -> libc::c_int;
+    fn av_seek_frame(
+        ctx: *mut AVFormatContext,
+        stream_index: libc::c_int,
+        timestamp: i64,
+        flags: libc::c_int,
+    ) -> libc::c_int;
+    fn av_write_frame(ctx: *mut AVFormatContext, p: *mut AVPacket) -> libc::c_int;
+    fn av_interleaved_write_frame(ctx: *mut AVFormatContext, p: *mut AVPacket) -> libc::c_int;
+    fn av_write_trailer(ctx: *mut AVFormatContext) -> libc::c_int;
     pub(crate) fn av_register_all();
     pub(crate) fn avformat_network_init() -> libc::c_int;
 }
@@ -79,16 +109,28 @@ extern "C" {
     static moonfire_ffmpeg_seek_cur: libc::c_int;
     static moonfire_ffmpeg_seek_end: libc::c_int;
 
+    static moonfire_ffmpeg_avseek_flag_backward: libc::c_int;
+    static moonfire_ffmpeg_avseek_flag_any: libc::c_int;
+    static moonfire_ffmpeg_av_time_base: libc::c_int;
+
     fn moonfire_ffmpeg_fctx_streams(ctx: *const AVFormatContext) -> StreamsLen;
-    //fn moonfire_ffmpeg_fctx_open_write(ctx: *mut AVFormatContext,
-    //                                   url: *const libc::c_char) -> libc::c_int;
-    //
+    fn moonfire_ffmpeg_fctx_open_write(
+        ctx: *mut AVFormatContext,
+        url: *const libc::c_char,
+    ) -> libc::c_int;
+    fn moonfire_ffmpeg_fctx_close_write(ctx: *mut AVFormatContext);
 
     fn moonfire_ffmpeg_fctx_set_pb(ctx: *mut AVFormatContext, pb: *mut AVIOContext);
 
+    /// Sets `ctx->pb` to `NULL` and returns its previous value, so the caller can take over
+    /// its lifetime (e.g. to hand it to `avio_close_dyn_buf`) without `avformat_free_context`
+    /// also trying to free it.
+    fn moonfire_ffmpeg_fctx_take_pb(ctx: *mut AVFormatContext) -> *mut AVIOContext;
+
     fn moonfire_ffmpeg_ioctx_set_direct(pb: *mut AVIOContext);
 
     fn moonfire_ffmpeg_stream_codecpar(stream: *const AVStream) -> *const AVCodecParameters;
+    fn moonfire_ffmpeg_stream_codecpar_mut(stream: *mut AVStream) -> *mut AVCodecParameters;
     fn moonfire_ffmpeg_stream_duration(stream: *const AVStream) -> i64;
     fn moonfire_ffmpeg_stream_time_base(stream: *const AVStream) -> crate::avutil::Rational;
 }
@@ -225,7 +267,8 @@ impl<'a> IoContext for SliceIoContext<'a> {
                 .checked_add(offset)
                 .ok_or_else(Error::invalid_data)?,
             Whence::End => self
-                .pos
+                .slice
+                .len()
                 .checked_add(offset)
                 .ok_or_else(Error::invalid_data)?,
         };
@@ -237,6 +280,179 @@ impl<'a> IoContext for SliceIoContext<'a> {
     }
 }
 
+/// An `IoContext` implementation backed by a growable in-memory buffer, readable, writable, and
+/// seekable all at once. Lets callers remux a recorded segment to/from memory with no
+/// filesystem or `avio_open_dyn_buf` involvement, e.g. `with_io_context` it into an
+/// `OutputFormatContext`, write a segment, then call `into_inner()` to get the muxed bytes.
+pub struct VecIoContext {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl VecIoContext {
+    pub fn new() -> Self {
+        VecIoContext {
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub fn from_vec(buf: Vec<u8>) -> Self {
+        VecIoContext { buf, pos: 0 }
+    }
+
+    /// Consumes this context, returning the accumulated bytes.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Default for VecIoContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IoContext for VecIoContext {
+    fn readable(&self) -> bool {
+        true
+    }
+    fn writable(&self) -> bool {
+        true
+    }
+    fn seekable(&self) -> bool {
+        true
+    }
+    fn buf_len(&self) -> usize {
+        4096
+    }
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let copy_len = std::cmp::min(buf.len(), self.buf.len() - self.pos);
+        buf[0..copy_len].copy_from_slice(&self.buf[self.pos..self.pos + copy_len]);
+        self.pos += copy_len;
+        Ok(copy_len)
+    }
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let end = self.pos + buf.len();
+        if end > self.buf.len() {
+            self.buf.resize(end, 0);
+        }
+        self.buf[self.pos..end].copy_from_slice(buf);
+        self.pos = end;
+        Ok(buf.len())
+    }
+    fn seek(&mut self, offset: i64, whence: Whence, _force: bool) -> Result<u64, Error> {
+        let offset = usize::try_from(offset).map_err(|_| Error::invalid_data())?;
+        let new_pos = match whence {
+            Whence::Size => return Ok(u64::try_from(self.buf.len()).unwrap()),
+            Whence::Set => offset,
+            Whence::Cur => self
+                .pos
+                .checked_add(offset)
+                .ok_or_else(Error::invalid_data)?,
+            Whence::End => self
+                .buf
+                .len()
+                .checked_add(offset)
+                .ok_or_else(Error::invalid_data)?,
+        };
+        if new_pos > self.buf.len() {
+            return Err(Error::invalid_data());
+        }
+        self.pos = new_pos;
+        Ok(u64::try_from(self.pos).unwrap())
+    }
+}
+
+/// Bridges any `std::io::{Read, Seek}` (e.g. a `File` or `Cursor`) into `IoContext`, so it can
+/// be fed directly to `InputFormatContext::with_io_context` without hand-rolling a trait impl.
+pub struct ReadSeekIoContext<T>(T);
+
+impl<T> ReadSeekIoContext<T> {
+    pub fn new(inner: T) -> Self {
+        ReadSeekIoContext(inner)
+    }
+}
+
+impl<T: std::io::Read + std::io::Seek> IoContext for ReadSeekIoContext<T> {
+    fn readable(&self) -> bool {
+        true
+    }
+    fn seekable(&self) -> bool {
+        true
+    }
+    fn buf_len(&self) -> usize {
+        4096
+    }
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.0.read(buf).map_err(io_error_to_avutil)
+    }
+    fn seek(&mut self, offset: i64, whence: Whence, _force: bool) -> Result<u64, Error> {
+        seek_read_seek(&mut self.0, offset, whence)
+    }
+}
+
+/// Bridges any `std::io::{Write, Seek}` into `IoContext`'s write side, analogous to
+/// `ReadSeekIoContext`.
+pub struct WriteSeekIoContext<T>(T);
+
+impl<T> WriteSeekIoContext<T> {
+    pub fn new(inner: T) -> Self {
+        WriteSeekIoContext(inner)
+    }
+}
+
+impl<T: std::io::Write + std::io::Seek> IoContext for WriteSeekIoContext<T> {
+    fn writable(&self) -> bool {
+        true
+    }
+    fn seekable(&self) -> bool {
+        true
+    }
+    fn buf_len(&self) -> usize {
+        4096
+    }
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.0.write(buf).map_err(io_error_to_avutil)
+    }
+    fn seek(&mut self, offset: i64, whence: Whence, _force: bool) -> Result<u64, Error> {
+        seek_read_seek(&mut self.0, offset, whence)
+    }
+}
+
+/// Shared `Whence`-to-`std::io::SeekFrom` translation for `ReadSeekIoContext`/`WriteSeekIoContext`.
+/// `Whence::Size` has no `Seek` equivalent, so it's implemented as a seek-to-end-and-back.
+fn seek_read_seek<T: std::io::Seek>(
+    inner: &mut T,
+    offset: i64,
+    whence: Whence,
+) -> Result<u64, Error> {
+    use std::io::SeekFrom;
+    let pos = match whence {
+        Whence::Size => {
+            let cur = inner.stream_position().map_err(io_error_to_avutil)?;
+            let end = inner.seek(SeekFrom::End(0)).map_err(io_error_to_avutil)?;
+            inner
+                .seek(SeekFrom::Start(cur))
+                .map_err(io_error_to_avutil)?;
+            return Ok(end);
+        }
+        Whence::Set => {
+            SeekFrom::Start(u64::try_from(offset).map_err(|_| Error::invalid_data())?)
+        }
+        Whence::Cur => SeekFrom::Current(offset),
+        Whence::End => SeekFrom::End(offset),
+    };
+    inner.seek(pos).map_err(io_error_to_avutil)
+}
+
+fn io_error_to_avutil(e: std::io::Error) -> Error {
+    match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => Error::eof(),
+        _ => Error::unknown(),
+    }
+}
+
 struct IoContextWrapper<'a> {
     // The opaque pointer passed to the callbacks must be thin, so create a box here so we have a
     // stable address to the fat pointer itself.
@@ -254,6 +470,9 @@ unsafe extern "C" fn ioctx_read_packet(
     let ctx: &mut &mut dyn IoContext = &mut *(opaque as *mut &mut dyn IoContext);
     let buf = std::slice::from_raw_parts_mut(buf_data, usize::try_from(buf_len).unwrap());
     match ctx.read(buf) {
+        // A zero-length read means end of stream; ffmpeg expects `AVERROR_EOF`; a literal `0`
+        // return would be taken as "no bytes yet, try again" and could spin forever.
+        Ok(0) => Error::eof().get(),
         Ok(l) => libc::c_int::try_from(l).unwrap(),
         Err(e) => e.get(),
     }
@@ -397,8 +616,12 @@ impl<'a> InputFormatContext<'a> {
         })
     }
 
-    pub fn find_stream_info(&mut self) -> Result<(), Error> {
-        Error::wrap(unsafe { avformat_find_stream_info(self.ctx, ptr::null_mut()) })?;
+    /// Reads packets to determine stream properties ffmpeg couldn't infer from just the
+    /// header, e.g. via the `AVFormatContext.streams[i]->codecpar` it fills in. `options` lets
+    /// callers tune probing (e.g. `analyzeduration`, `probesize`) the same way `open`/
+    /// `with_io_context` tune opening; pass an empty `Dictionary` to keep ffmpeg's defaults.
+    pub fn find_stream_info(&mut self, options: &mut Dictionary) -> Result<(), Error> {
+        Error::wrap(unsafe { avformat_find_stream_info(self.ctx, options) })?;
         Ok(())
     }
 
@@ -416,6 +639,85 @@ impl<'a> InputFormatContext<'a> {
             std::slice::from_raw_parts(s.streams, s.len as usize)
         })
     }
+
+    /// Finds the "best" stream of `media_type` using ffmpeg's heuristics (bitrate,
+    /// default-disposition, etc), analogous to the stream selection ffmpeg's CLI does for an
+    /// unqualified `-map 0:v`/`-map 0:a`. Returns `None` if there's no such stream.
+    pub fn best_stream(&self, media_type: MediaType) -> Option<(usize, InputStream<'_>)> {
+        let ret = unsafe {
+            av_find_best_stream(self.ctx, media_type.raw(), -1, -1, ptr::null_mut(), 0)
+        };
+        if ret < 0 {
+            return None;
+        }
+        let i = usize::try_from(ret).unwrap();
+        Some((i, self.streams().get(i)))
+    }
+
+    /// Repositions the stream to a keyframe near `timestamp` (at or before it with
+    /// `SeekFlags::backward()`, otherwise possibly just after it), in the time base of
+    /// `stream_index`'s stream (or fractional `AV_TIME_BASE` seconds across the whole file if
+    /// `stream_index` is `None`). This lets a caller extract a clip around a given moment
+    /// without demuxing everything before it.
+    pub fn seek(
+        &self,
+        stream_index: Option<usize>,
+        timestamp: i64,
+        flags: SeekFlags,
+    ) -> Result<(), Error> {
+        let stream_index = stream_index.map_or(-1, |i| libc::c_int::try_from(i).unwrap());
+        Error::wrap(unsafe { av_seek_frame(self.ctx, stream_index, timestamp, flags.0) })?;
+        Ok(())
+    }
+
+    /// Convenience wrapping `seek`: converts `time` (from the start of `stream_index`'s stream,
+    /// or of the file as a whole if `None`) from seconds into the relevant time base.
+    pub fn seek_time(
+        &self,
+        stream_index: Option<usize>,
+        time: std::time::Duration,
+        flags: SeekFlags,
+    ) -> Result<(), Error> {
+        let timestamp = match stream_index {
+            Some(i) => {
+                let tb = self.streams().get(i).time_base();
+                (time.as_secs_f64() * f64::from(tb.den) / f64::from(tb.num)).round() as i64
+            }
+            None => {
+                (time.as_secs_f64() * f64::from(unsafe { moonfire_ffmpeg_av_time_base })).round() as i64
+            }
+        };
+        self.seek(stream_index, timestamp, flags)
+    }
+}
+
+/// Flags controlling `InputFormatContext::seek`, mirroring ffmpeg's `AVSEEK_FLAG_*` bitmask.
+#[derive(Copy, Clone, Default)]
+pub struct SeekFlags(libc::c_int);
+
+impl SeekFlags {
+    pub fn none() -> Self {
+        SeekFlags(0)
+    }
+
+    /// Seeks to the nearest keyframe at or before `timestamp`, rather than possibly landing
+    /// just after it.
+    pub fn backward() -> Self {
+        SeekFlags(unsafe { moonfire_ffmpeg_avseek_flag_backward })
+    }
+
+    /// Allows seeking to non-keyframes; the caller is responsible for decoding from the
+    /// preceding keyframe if it needs a displayable frame.
+    pub fn any() -> Self {
+        SeekFlags(unsafe { moonfire_ffmpeg_avseek_flag_any })
+    }
+}
+
+impl std::ops::BitOr for SeekFlags {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        SeekFlags(self.0 | rhs.0)
+    }
 }
 
 unsafe impl<'a> Send for InputFormatContext<'a> {}
@@ -466,6 +768,179 @@ impl<'o> InputStream<'o> {
     }
 }
 
+/// A muxer: the write-side counterpart of `InputFormatContext`.
+pub struct OutputFormatContext<'a> {
+    /// See `InputFormatContext::_io_ctx`.
+    _io_ctx: PhantomData<&'a mut dyn IoContext>,
+    ctx: *mut AVFormatContext,
+
+    /// True if `open` was used to write to a filesystem path/URL, in which case `drop` must
+    /// close the `AVIOContext` it opened internally. Not set when a caller-supplied `IoContext`
+    /// is attached via `with_io_context`; the caller/`IoContextWrapper` owns that one instead.
+    opened: bool,
+
+    /// True if `with_dyn_buf` attached an in-memory `avio_open_dyn_buf` buffer that hasn't yet
+    /// been retrieved via `close_dyn_buf`, in which case `drop` must close and discard it.
+    dyn_buf: bool,
+
+    /// The `AVIOContext` attached by `with_io_context`, freed when this is dropped.
+    io_wrapper: Option<IoContextWrapper<'a>>,
+}
+
+impl<'a> OutputFormatContext<'a> {
+    /// Allocates a muxer for `format_name` (e.g. `c"mp4"`) and/or `filename`, letting ffmpeg
+    /// guess whichever one is omitted from the other.
+    pub fn new(format_name: Option<&CStr>, filename: Option<&CStr>) -> Result<Self, Error> {
+        let mut ctx = ptr::null_mut();
+        Error::wrap(unsafe {
+            avformat_alloc_output_context2(
+                &mut ctx,
+                ptr::null(),
+                format_name.map_or(ptr::null(), CStr::as_ptr),
+                filename.map_or(ptr::null(), CStr::as_ptr),
+            )
+        })?;
+        if ctx.is_null() {
+            return Err(Error::unknown());
+        }
+        Ok(OutputFormatContext {
+            _io_ctx: PhantomData,
+            ctx,
+            opened: false,
+            dyn_buf: false,
+            io_wrapper: None,
+        })
+    }
+
+    /// Like `new`, but attaches `io_ctx` as the muxer's output instead of a filesystem path.
+    pub fn with_io_context(format_name: &CStr, io_ctx: &'a mut dyn IoContext) -> Result<Self, Error> {
+        let mut this = Self::new(Some(format_name), None)?;
+        let wrapper = IoContextWrapper::new(io_ctx)?;
+        unsafe { moonfire_ffmpeg_fctx_set_pb(this.ctx, wrapper.avio_ctx.as_ptr()) };
+        this.io_wrapper = Some(wrapper);
+        Ok(this)
+    }
+
+    /// Like `new`, but attaches an in-memory `avio_open_dyn_buf` buffer as the muxer's output,
+    /// so the muxed bytes can be retrieved as a `Vec<u8>` via `close_dyn_buf` after
+    /// `write_trailer`, with no filesystem or caller-supplied `IoContext` involved. Useful for
+    /// producing a small fragmented-MP4 segment entirely in RAM, e.g. to hand to an HTTP
+    /// response.
+    pub fn with_dyn_buf(format_name: &CStr) -> Result<Self, Error> {
+        let mut this = Self::new(Some(format_name), None)?;
+        let mut pb = ptr::null_mut();
+        Error::wrap(unsafe { avio_open_dyn_buf(&mut pb) })?;
+        unsafe { moonfire_ffmpeg_fctx_set_pb(this.ctx, pb) };
+        this.dyn_buf = true;
+        Ok(this)
+    }
+
+    /// Opens `filename` for writing. Must not be called on a context created via
+    /// `with_io_context`, which already has its `AVIOContext` attached.
+    pub fn open(&mut self, filename: &CStr) -> Result<(), Error> {
+        Error::wrap(unsafe { moonfire_ffmpeg_fctx_open_write(self.ctx, filename.as_ptr()) })?;
+        self.opened = true;
+        Ok(())
+    }
+
+    /// Adds a new output stream, copying its codec parameters from `src` (typically an
+    /// `InputStream::codecpar()` for a straight remux, or an encoder's `AVCodecContext`'s
+    /// parameters for a transcode). Returns the new stream's index, for later use with
+    /// `stream_time_base`.
+    pub fn new_stream(&mut self, src: &AVCodecParameters) -> Result<usize, Error> {
+        let index = unsafe { moonfire_ffmpeg_fctx_streams(self.ctx) }.len as usize;
+        let st = ptr::NonNull::new(unsafe { avformat_new_stream(self.ctx, ptr::null()) })
+            .ok_or_else(Error::enomem)?;
+        let dst = unsafe { moonfire_ffmpeg_stream_codecpar_mut(st.as_ptr()) };
+        Error::wrap(unsafe { avcodec_parameters_copy(dst, src) })?;
+        Ok(index)
+    }
+
+    pub fn write_header(&mut self, options: &mut Dictionary) -> Result<(), Error> {
+        Error::wrap(unsafe { avformat_write_header(self.ctx, options) })?;
+        Ok(())
+    }
+
+    /// Returns output stream `index`'s time base, as assigned by the muxer (many muxers, e.g.
+    /// mp4/mp3, override whatever was in the `AVCodecParameters` passed to `new_stream` once
+    /// `write_header` runs). This is the `to_tb` callers of `write_frame`/
+    /// `interleaved_write_frame` need; call it only after `write_header`.
+    pub fn stream_time_base(&self, index: usize) -> Rational {
+        let streams = unsafe { moonfire_ffmpeg_fctx_streams(self.ctx) };
+        assert!(index < streams.len as usize);
+        unsafe { moonfire_ffmpeg_stream_time_base(*streams.streams.add(index)) }
+    }
+
+    /// Rescales `pkt`'s timestamps from `from_tb` to the appropriate output stream's time base
+    /// and writes it, without the buffering/reordering `interleaved_write_frame` does.
+    pub fn write_frame(&mut self, pkt: &mut Packet, from_tb: Rational, to_tb: Rational) -> Result<(), Error> {
+        unsafe {
+            av_packet_rescale_ts(*pkt.0, from_tb, to_tb);
+        }
+        Error::wrap(unsafe { av_write_frame(self.ctx, *pkt.0) })?;
+        Ok(())
+    }
+
+    /// Like `write_frame`, but lets ffmpeg buffer and interleave packets across streams so they
+    /// come out in the order the container format requires. This is the usual choice for muxing
+    /// more than one stream.
+    pub fn interleaved_write_frame(
+        &mut self,
+        pkt: &mut Packet,
+        from_tb: Rational,
+        to_tb: Rational,
+    ) -> Result<(), Error> {
+        unsafe {
+            av_packet_rescale_ts(*pkt.0, from_tb, to_tb);
+        }
+        Error::wrap(unsafe { av_interleaved_write_frame(self.ctx, *pkt.0) })?;
+        Ok(())
+    }
+
+    pub fn write_trailer(&mut self) -> Result<(), Error> {
+        Error::wrap(unsafe { av_write_trailer(self.ctx) })?;
+        Ok(())
+    }
+
+    /// Finishes a muxer created via `with_dyn_buf`, returning the bytes written so far (the
+    /// full muxed output, if called after `write_trailer`). Consumes `self` because the
+    /// `AVIOContext` it wraps is closed in the process.
+    pub fn close_dyn_buf(mut self) -> Result<Vec<u8>, Error> {
+        assert!(self.dyn_buf, "close_dyn_buf called on a non-dyn-buf OutputFormatContext");
+        self.dyn_buf = false;
+        unsafe {
+            let pb = moonfire_ffmpeg_fctx_take_pb(self.ctx);
+            let mut buf: *mut u8 = ptr::null_mut();
+            let len = avio_close_dyn_buf(pb, &mut buf);
+            if buf.is_null() {
+                return Err(Error::wrap(len).err().unwrap_or_else(Error::unknown));
+            }
+            let len = usize::try_from(len).map_err(|_| Error::unknown())?;
+            let out = std::slice::from_raw_parts(buf, len).to_vec();
+            av_free(buf as *mut libc::c_void);
+            Ok(out)
+        }
+    }
+}
+
+impl<'a> Drop for OutputFormatContext<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            if self.dyn_buf {
+                let pb = moonfire_ffmpeg_fctx_take_pb(self.ctx);
+                let mut buf: *mut u8 = ptr::null_mut();
+                avio_close_dyn_buf(pb, &mut buf);
+                if !buf.is_null() {
+                    av_free(buf as *mut libc::c_void);
+                }
+            } else if self.opened {
+                moonfire_ffmpeg_fctx_close_write(self.ctx);
+            }
+            avformat_free_context(self.ctx);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use cstr::cstr;
@@ -495,6 +970,96 @@ mod test {
         assert_eq!(pts, &[0, 29700, 59400, 90000, 119700, 149400]);
     }
 
+    /// An end-to-end `send_packet`/`receive_frame` decode of `clip.mp4`'s video stream, checking
+    /// that every packet yields a real decoded frame (not just that the decode calls don't
+    /// error).
+    #[test]
+    fn decode() {
+        use crate::avutil::{Dictionary, MediaType, VideoFrame};
+        crate::Ffmpeg::new();
+        let mut dict = Dictionary::new();
+        let mut ctx =
+            super::InputFormatContext::open(cstr!("src/testdata/clip.mp4"), &mut dict).unwrap();
+        ctx.find_stream_info(&mut Dictionary::new()).unwrap();
+        let (_, stream) = ctx.best_stream(MediaType::video()).unwrap();
+        let decoder = stream
+            .codecpar()
+            .new_decoder(&mut Dictionary::new())
+            .unwrap();
+        let mut frame = VideoFrame::empty().unwrap();
+        let mut frame_count = 0;
+        with_packets(&mut ctx, |pkt| {
+            decoder.send_packet(Some(&pkt)).unwrap();
+            while decoder.receive_frame(&mut frame).unwrap() {
+                frame_count += 1;
+            }
+        });
+        decoder.send_packet(None).unwrap();
+        while decoder.receive_frame(&mut frame).unwrap() {
+            frame_count += 1;
+        }
+        assert_eq!(frame_count, 6);
+        assert!(frame.dims().width > 0 && frame.dims().height > 0);
+    }
+
+    /// An end-to-end remux of `clip.mp4`'s video stream into an in-memory mp4 via
+    /// `OutputFormatContext::with_dyn_buf`, exercising `new_stream`/`write_header`/
+    /// `stream_time_base`/`interleaved_write_frame`/`write_trailer`/`close_dyn_buf` together.
+    #[test]
+    fn mux() {
+        use crate::avutil::{Dictionary, MediaType};
+        crate::Ffmpeg::new();
+        let mut dict = Dictionary::new();
+        let mut in_ctx =
+            super::InputFormatContext::open(cstr!("src/testdata/clip.mp4"), &mut dict).unwrap();
+        in_ctx.find_stream_info(&mut Dictionary::new()).unwrap();
+        let (in_index, in_stream) = in_ctx.best_stream(MediaType::video()).unwrap();
+        let from_tb = in_stream.time_base();
+        let codecpar = in_stream.codecpar();
+
+        let mut out_ctx = super::OutputFormatContext::with_dyn_buf(cstr!("mp4")).unwrap();
+        let out_index = out_ctx.new_stream(&codecpar).unwrap();
+        out_ctx.write_header(&mut Dictionary::new()).unwrap();
+        let to_tb = out_ctx.stream_time_base(out_index);
+
+        let mut pkt_count = 0;
+        loop {
+            let mut pkt = match in_ctx.read_frame() {
+                Err(e) if e.is_eof() => break,
+                Err(e) => panic!("{}", e),
+                Ok(p) => p,
+            };
+            if pkt.stream_index() != in_index {
+                continue;
+            }
+            out_ctx
+                .interleaved_write_frame(&mut pkt, from_tb, to_tb)
+                .unwrap();
+            pkt_count += 1;
+        }
+        out_ctx.write_trailer().unwrap();
+        let bytes = out_ctx.close_dyn_buf().unwrap();
+        assert!(pkt_count > 0);
+        assert!(!bytes.is_empty());
+    }
+
+    #[test]
+    fn vec_io_context_round_trip() {
+        use super::{IoContext, VecIoContext, Whence};
+        let mut io_ctx = VecIoContext::new();
+        assert_eq!(io_ctx.write(b"hello world").unwrap(), 11);
+        assert_eq!(io_ctx.seek(0, Whence::Set, false).unwrap(), 0);
+        let mut buf = [0u8; 5];
+        assert_eq!(io_ctx.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+        assert_eq!(io_ctx.seek(0, Whence::Size, false).unwrap(), 11);
+
+        // Seeking past the end of the buffer must be rejected, not silently succeed and leave
+        // a later `read()` to underflow/panic.
+        assert!(io_ctx.seek(1, Whence::End, false).is_err());
+        assert!(io_ctx.seek(100, Whence::Set, false).is_err());
+    }
+
     // Directly reference it as a slice.
     #[test]
     fn slice() {
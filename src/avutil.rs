@@ -3,6 +3,7 @@
 
 use std::convert::TryFrom;
 use std::ffi::CStr;
+use std::iter::FromIterator;
 use std::ptr;
 
 //#[link(name = "avutil")]
@@ -23,9 +24,28 @@ extern "C" {
         flags: libc::c_int,
     ) -> libc::c_int;
     fn av_dict_free(d: *mut *mut AVDictionary);
-    fn av_frame_alloc() -> *mut AVFrame;
-    fn av_frame_free(f: *mut *mut AVFrame);
+    fn av_dict_parse_string(
+        pm: *mut *mut AVDictionary,
+        str: *const libc::c_char,
+        key_val_sep: *const libc::c_char,
+        pairs_sep: *const libc::c_char,
+        flags: libc::c_int,
+    ) -> libc::c_int;
+    pub(crate) fn av_frame_alloc() -> *mut AVFrame;
+    pub(crate) fn av_frame_free(f: *mut *mut AVFrame);
     fn av_get_pix_fmt_name(fmt: libc::c_int) -> *const libc::c_char;
+    fn av_pix_fmt_count_planes(pix_fmt: libc::c_int) -> libc::c_int;
+    fn av_malloc(size: libc::size_t) -> *mut libc::c_void;
+    pub(crate) fn av_free(ptr: *mut libc::c_void);
+
+    fn av_reduce(
+        dst_num: *mut libc::c_int,
+        dst_den: *mut libc::c_int,
+        num: i64,
+        den: i64,
+        max: i64,
+    ) -> libc::c_int;
+    fn av_rescale_q_rnd(a: i64, bq: Rational, cq: Rational, rnd: libc::c_int) -> i64;
 }
 
 //#[link(name = "wrapper")]
@@ -35,20 +55,42 @@ extern "C" {
     pub(crate) static moonfire_ffmpeg_av_nopts_value: i64;
 
     static moonfire_ffmpeg_averror_eof: libc::c_int;
+    static moonfire_ffmpeg_averror_eagain: libc::c_int;
     static moonfire_ffmpeg_averror_enomem: libc::c_int;
+    static moonfire_ffmpeg_averror_enosys: libc::c_int;
+    static moonfire_ffmpeg_averror_invaliddata: libc::c_int;
     static moonfire_ffmpeg_averror_decoder_not_found: libc::c_int;
     static moonfire_ffmpeg_averror_unknown: libc::c_int;
 
     static moonfire_ffmpeg_avmedia_type_video: libc::c_int;
+    static moonfire_ffmpeg_avmedia_type_audio: libc::c_int;
+
+    static moonfire_ffmpeg_av_picture_type_none: libc::c_int;
+    static moonfire_ffmpeg_av_picture_type_i: libc::c_int;
+    static moonfire_ffmpeg_av_picture_type_p: libc::c_int;
+    static moonfire_ffmpeg_av_picture_type_b: libc::c_int;
+
+    static moonfire_ffmpeg_av_round_near_inf: libc::c_int;
+    static moonfire_ffmpeg_av_round_pass_minmax: libc::c_int;
 
     static moonfire_ffmpeg_pix_fmt_rgb24: libc::c_int;
     static moonfire_ffmpeg_pix_fmt_bgr24: libc::c_int;
+    static moonfire_ffmpeg_pix_fmt_yuv420p: libc::c_int;
+    static moonfire_ffmpeg_pix_fmt_nv12: libc::c_int;
+    static moonfire_ffmpeg_pix_fmt_gray8: libc::c_int;
 
     fn moonfire_ffmpeg_frame_image_alloc(
         f: *mut AVFrame,
         dims: *const ImageDimensions,
     ) -> libc::c_int;
     pub(crate) fn moonfire_ffmpeg_frame_stuff(frame: *const AVFrame, stuff: *mut FrameStuff);
+
+    /// Fills `desc` from `av_pix_fmt_desc_get(pix_fmt)`, returning 0 on success or a negative
+    /// value if `pix_fmt` is unrecognized (in which case `av_pix_fmt_desc_get` returned `NULL`).
+    fn moonfire_ffmpeg_pix_fmt_descriptor(
+        pix_fmt: libc::c_int,
+        desc: *mut PixFmtDescStuff,
+    ) -> libc::c_int;
 }
 
 // No accessors here; seems reasonable to assume ABI stability of this simple struct.
@@ -67,6 +109,65 @@ pub struct Rational {
     pub den: libc::c_int,
 }
 
+impl Rational {
+    pub fn new(num: libc::c_int, den: libc::c_int) -> Self {
+        Rational { num, den }
+    }
+
+    /// Reduces `num/den` to lowest terms via `av_reduce`, clamping the numerator/denominator
+    /// magnitude to `max`.
+    pub fn reduced(num: i64, den: i64, max: i64) -> Self {
+        let mut dst_num = 0;
+        let mut dst_den = 0;
+        unsafe { av_reduce(&mut dst_num, &mut dst_den, num, den, max) };
+        Rational {
+            num: dst_num,
+            den: dst_den,
+        }
+    }
+
+    pub fn invert(self) -> Self {
+        Rational {
+            num: self.den,
+            den: self.num,
+        }
+    }
+
+    pub fn as_f64(self) -> f64 {
+        f64::from(self.num) / f64::from(self.den)
+    }
+
+    /// Converts `value`, a timestamp/duration in `from`'s units, into `to`'s units, using the
+    /// same `AV_ROUND_NEAR_INF | AV_ROUND_PASS_MINMAX` rounding ffmpeg itself uses for
+    /// timestamps. Passes `AV_NOPTS_VALUE` through unchanged rather than rescaling it as if it
+    /// were a real timestamp.
+    pub fn rescale(value: i64, from: Rational, to: Rational) -> i64 {
+        if value == unsafe { moonfire_ffmpeg_av_nopts_value } {
+            return value;
+        }
+        let rnd =
+            unsafe { moonfire_ffmpeg_av_round_near_inf | moonfire_ffmpeg_av_round_pass_minmax };
+        unsafe { av_rescale_q_rnd(value, from, to, rnd) }
+    }
+}
+
+impl std::ops::Mul for Rational {
+    type Output = Rational;
+    fn mul(self, rhs: Rational) -> Rational {
+        Rational {
+            num: self.num * rhs.num,
+            den: self.den * rhs.den,
+        }
+    }
+}
+
+impl std::ops::Div for Rational {
+    type Output = Rational;
+    fn div(self, rhs: Rational) -> Rational {
+        self * rhs.invert()
+    }
+}
+
 // No ABI stability assumption here; use heap allocation/deallocation and accessors only.
 enum AVDictionary {}
 pub(crate) enum AVFrame {}
@@ -77,6 +178,10 @@ pub(crate) struct FrameStuff {
     pub(crate) data: *const *mut u8,
     pub(crate) linesizes: *const libc::c_int,
     pts: i64,
+    key_frame: libc::c_int,
+    pict_type: libc::c_int,
+    best_effort_timestamp: i64,
+    pkt_dts: i64,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -94,9 +199,21 @@ pub struct VideoParameters {
 pub struct MediaType(libc::c_int);
 
 impl MediaType {
+    pub fn video() -> Self {
+        MediaType(unsafe { moonfire_ffmpeg_avmedia_type_video })
+    }
+
+    pub fn audio() -> Self {
+        MediaType(unsafe { moonfire_ffmpeg_avmedia_type_audio })
+    }
+
     pub fn is_video(self) -> bool {
         self.0 == unsafe { moonfire_ffmpeg_avmedia_type_video }
     }
+
+    pub(crate) fn raw(self) -> libc::c_int {
+        self.0
+    }
 }
 
 pub struct VideoFrame {
@@ -111,6 +228,13 @@ pub struct Plane<'f> {
     pub height: usize,
 }
 
+pub struct PlaneMut<'f> {
+    pub data: &'f mut [u8],
+    pub linesize: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
 impl VideoFrame {
     /// Creates a new `VideoFrame` which is empty: no allocated storage (reference-counted or
     /// otherwise). Can be filled via `DecodeContext::decode_video`.
@@ -127,6 +251,10 @@ impl VideoFrame {
                 data: ptr::null(),
                 linesizes: ptr::null(),
                 pts: 0,
+                key_frame: 0,
+                pict_type: 0,
+                best_effort_timestamp: 0,
+                pkt_dts: 0,
             },
         })
     }
@@ -140,6 +268,32 @@ impl VideoFrame {
         Ok(frame)
     }
 
+    /// Returns the width (in pixels) of the given plane, accounting for chroma subsampling: for
+    /// planar formats like YUV420P, the chroma planes (1 and 2) have fewer columns than the
+    /// luma plane (0).
+    fn plane_width(&self, plane: usize) -> usize {
+        let width = self.stuff.dims.width;
+        if plane == 1 || plane == 2 {
+            if let Some(desc) = self.stuff.dims.pix_fmt.descriptor() {
+                return ceil_rshift(width, desc.log2_chroma_w()) as usize;
+            }
+        }
+        width as usize
+    }
+
+    /// Returns the height (in rows) of the given plane, accounting for chroma subsampling: for
+    /// planar formats like YUV420P, the chroma planes (1 and 2) have fewer rows than the luma
+    /// plane (0).
+    fn plane_height(&self, plane: usize) -> usize {
+        let height = self.stuff.dims.height;
+        if plane == 1 || plane == 2 {
+            if let Some(desc) = self.stuff.dims.pix_fmt.descriptor() {
+                return ceil_rshift(height, desc.log2_chroma_h()) as usize;
+            }
+        }
+        height as usize
+    }
+
     pub fn plane(&self, plane: usize) -> Plane {
         assert!(plane < 8);
         let plane_off = isize::try_from(plane).unwrap();
@@ -148,8 +302,8 @@ impl VideoFrame {
         assert!(!d.is_null());
         assert!(l > 0);
         let l = l as usize;
-        let width = self.stuff.dims.width as usize;
-        let height = self.stuff.dims.height as usize;
+        let width = self.plane_width(plane);
+        let height = self.plane_height(plane);
         Plane {
             data: unsafe { std::slice::from_raw_parts(d, l * height) },
             linesize: l,
@@ -158,12 +312,89 @@ impl VideoFrame {
         }
     }
 
+    /// Like `plane`, but allows writing into the plane's backing storage.
+    pub fn plane_mut(&mut self, plane: usize) -> PlaneMut {
+        assert!(plane < 8);
+        let plane_off = isize::try_from(plane).unwrap();
+        let d = unsafe { *self.stuff.data.offset(plane_off) };
+        let l = unsafe { *self.stuff.linesizes.offset(plane_off) };
+        assert!(!d.is_null());
+        assert!(l > 0);
+        let l = l as usize;
+        let width = self.plane_width(plane);
+        let height = self.plane_height(plane);
+        PlaneMut {
+            data: unsafe { std::slice::from_raw_parts_mut(d, l * height) },
+            linesize: l,
+            width,
+            height,
+        }
+    }
+
     pub fn dims(&self) -> ImageDimensions {
         self.stuff.dims
     }
     pub fn pts(&self) -> i64 {
         self.stuff.pts
     }
+
+    /// Returns true iff this frame is a keyframe (doesn't depend on other frames to decode),
+    /// e.g. for building a seekable index from a decode pass.
+    pub fn key_frame(&self) -> bool {
+        self.stuff.key_frame != 0
+    }
+
+    pub fn picture_type(&self) -> PictureType {
+        PictureType::from_raw(self.stuff.pict_type)
+    }
+
+    /// ffmpeg's heuristically-corrected presentation timestamp, which may differ from `pts()`
+    /// when ffmpeg needed to correct container timing. `None` if unset (`AV_NOPTS_VALUE`).
+    pub fn best_effort_timestamp(&self) -> Option<i64> {
+        nopts_to_option(self.stuff.best_effort_timestamp)
+    }
+
+    /// The decompression timestamp copied from the packet this frame was decoded from. `None`
+    /// if unset (`AV_NOPTS_VALUE`).
+    pub fn pkt_dts(&self) -> Option<i64> {
+        nopts_to_option(self.stuff.pkt_dts)
+    }
+}
+
+fn nopts_to_option(v: i64) -> Option<i64> {
+    if v == unsafe { moonfire_ffmpeg_av_nopts_value } {
+        None
+    } else {
+        Some(v)
+    }
+}
+
+/// A decoded frame's `AVPictureType`: whether it's an I/P/B frame, or some other/unknown type.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PictureType {
+    None,
+    I,
+    P,
+    B,
+    Other(libc::c_int),
+}
+
+impl PictureType {
+    fn from_raw(raw: libc::c_int) -> Self {
+        unsafe {
+            if raw == moonfire_ffmpeg_av_picture_type_none {
+                PictureType::None
+            } else if raw == moonfire_ffmpeg_av_picture_type_i {
+                PictureType::I
+            } else if raw == moonfire_ffmpeg_av_picture_type_p {
+                PictureType::P
+            } else if raw == moonfire_ffmpeg_av_picture_type_b {
+                PictureType::B
+            } else {
+                PictureType::Other(raw)
+            }
+        }
+    }
 }
 
 impl Drop for VideoFrame {
@@ -187,6 +418,79 @@ impl PixelFormat {
     pub fn bgr24() -> Self {
         PixelFormat(unsafe { moonfire_ffmpeg_pix_fmt_bgr24 })
     }
+    pub fn yuv420p() -> Self {
+        PixelFormat(unsafe { moonfire_ffmpeg_pix_fmt_yuv420p })
+    }
+    pub fn nv12() -> Self {
+        PixelFormat(unsafe { moonfire_ffmpeg_pix_fmt_nv12 })
+    }
+    pub fn gray8() -> Self {
+        PixelFormat(unsafe { moonfire_ffmpeg_pix_fmt_gray8 })
+    }
+
+    /// Looks up this format's `av_pix_fmt_desc_get` descriptor, or `None` if it's not a
+    /// recognized format.
+    pub fn descriptor(self) -> Option<PixelFormatDescriptor> {
+        let mut stuff = PixFmtDescStuff {
+            nb_components: 0,
+            bits_per_component: 0,
+            log2_chroma_w: 0,
+            log2_chroma_h: 0,
+        };
+        if unsafe { moonfire_ffmpeg_pix_fmt_descriptor(self.0, &mut stuff) } < 0 {
+            return None;
+        }
+        let nb_planes = unsafe { av_pix_fmt_count_planes(self.0) };
+        Some(PixelFormatDescriptor {
+            stuff,
+            nb_planes,
+        })
+    }
+}
+
+/// Matches `moonfire_ffmpeg_pix_fmt_descriptor`'s output struct.
+#[repr(C)]
+struct PixFmtDescStuff {
+    nb_components: libc::c_int,
+    bits_per_component: libc::c_int,
+    log2_chroma_w: libc::c_int,
+    log2_chroma_h: libc::c_int,
+}
+
+/// A `PixelFormat`'s component/subsampling layout, from `av_pix_fmt_desc_get`. Lets callers
+/// (e.g. `VideoFrame::plane`) compute correct per-plane dimensions for chroma-subsampled planar
+/// formats like YUV420P, rather than assuming every plane has the frame's full height.
+pub struct PixelFormatDescriptor {
+    stuff: PixFmtDescStuff,
+    nb_planes: libc::c_int,
+}
+
+impl PixelFormatDescriptor {
+    pub fn nb_components(&self) -> i32 {
+        self.stuff.nb_components
+    }
+    pub fn bits_per_component(&self) -> i32 {
+        self.stuff.bits_per_component
+    }
+    pub fn log2_chroma_w(&self) -> i32 {
+        self.stuff.log2_chroma_w
+    }
+    pub fn log2_chroma_h(&self) -> i32 {
+        self.stuff.log2_chroma_h
+    }
+    pub fn nb_planes(&self) -> i32 {
+        self.nb_planes
+    }
+}
+
+/// `AV_CEIL_RSHIFT(value, shift)`: rounds `value >> shift` up instead of down, e.g. to compute a
+/// chroma plane's height from a chroma-subsampled format's luma height.
+fn ceil_rshift(value: i32, shift: i32) -> i32 {
+    if shift <= 0 {
+        value
+    } else {
+        -(-value >> shift)
+    }
 }
 
 impl std::fmt::Debug for PixelFormat {
@@ -215,6 +519,11 @@ impl Error {
     pub fn eof() -> Self {
         Error(unsafe { moonfire_ffmpeg_averror_eof })
     }
+    /// Returns `AVERROR(EAGAIN)`: the decoder/encoder needs more input (or the caller needs to
+    /// drain more output) before it can make progress.
+    pub fn again() -> Self {
+        Error(unsafe { moonfire_ffmpeg_averror_eagain })
+    }
     pub fn enomem() -> Self {
         Error(unsafe { moonfire_ffmpeg_averror_enomem })
     }
@@ -224,6 +533,19 @@ impl Error {
     pub fn decoder_not_found() -> Self {
         Error(unsafe { moonfire_ffmpeg_averror_decoder_not_found })
     }
+    /// Returns `AVERROR(ENOSYS)`: the operation isn't implemented (e.g. a read on a
+    /// write-only `IoContext`).
+    pub fn enosys() -> Self {
+        Error(unsafe { moonfire_ffmpeg_averror_enosys })
+    }
+    pub fn invalid_data() -> Self {
+        Error(unsafe { moonfire_ffmpeg_averror_invaliddata })
+    }
+
+    /// Returns the raw `AVERROR` code, for handing back to an ffmpeg callback that expects one.
+    pub(crate) fn get(self) -> libc::c_int {
+        self.0
+    }
 
     /// Wraps the given return code as a Result: positive values are propagated through; negative
     /// values are turned into an `Error`.
@@ -237,6 +559,29 @@ impl Error {
     pub fn is_eof(self) -> bool {
         self.0 == unsafe { moonfire_ffmpeg_averror_eof }
     }
+
+    /// Returns true iff this is `AVERROR(EAGAIN)`: decode/encode loops should treat this as
+    /// "try again once more input/output is available", not a real failure.
+    pub fn is_again(self) -> bool {
+        self.0 == unsafe { moonfire_ffmpeg_averror_eagain }
+    }
+
+    pub fn is_invalid_data(self) -> bool {
+        self.0 == unsafe { moonfire_ffmpeg_averror_invaliddata }
+    }
+
+    /// If this wraps a POSIX errno (i.e. it's `AVERROR(e)` for some `e`, as opposed to one of
+    /// ffmpeg's own four-character-tag-based codes like `AVERROR_EOF`), returns that `errno`
+    /// value. ffmpeg's own codes are constructed via `FFERRTAG` and land far outside the valid
+    /// errno range, so a magnitude check is enough to tell the two apart.
+    pub fn errno(self) -> Option<libc::c_int> {
+        let e = -self.0;
+        if (1..4096).contains(&e) {
+            Some(e)
+        } else {
+            None
+        }
+    }
 }
 
 impl std::error::Error for Error {}
@@ -277,6 +622,102 @@ impl Dictionary {
         Error::wrap(unsafe { av_dict_set(&mut self.0, key.as_ptr(), value.as_ptr(), 0) })?;
         Ok(())
     }
+
+    /// Looks up `key`, returning `None` if it's not present.
+    pub fn get(&self, key: &CStr) -> Option<&CStr> {
+        let ent = unsafe { av_dict_get(self.0, key.as_ptr(), ptr::null_mut(), 0) };
+        if ent.is_null() {
+            return None;
+        }
+        Some(unsafe { CStr::from_ptr((*ent).value) })
+    }
+
+    /// Iterates over all `(key, value)` pairs.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            dict: self,
+            ent: ptr::null_mut(),
+        }
+    }
+
+    /// Builds a `Dictionary` from `(key, value)` pairs, e.g. `Dictionary::from_pairs([(k1, v1),
+    /// (k2, v2)])`.
+    pub fn from_pairs<'a, I>(pairs: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (&'a CStr, &'a CStr)>,
+    {
+        let mut d = Dictionary::new();
+        for (k, v) in pairs {
+            d.set(k, v)?;
+        }
+        Ok(d)
+    }
+
+    /// Loads a `"key=val:key2=val2"`-style blob, as accepted by many ffmpeg CLI flags, via
+    /// `av_dict_parse_string`.
+    pub fn parse_string(
+        &mut self,
+        s: &CStr,
+        key_val_sep: &CStr,
+        pairs_sep: &CStr,
+    ) -> Result<(), Error> {
+        Error::wrap(unsafe {
+            av_dict_parse_string(
+                &mut self.0,
+                s.as_ptr(),
+                key_val_sep.as_ptr(),
+                pairs_sep.as_ptr(),
+                0,
+            )
+        })?;
+        Ok(())
+    }
+}
+
+/// Iterator over a `Dictionary`'s `(key, value)` pairs, returned by `Dictionary::iter`.
+pub struct Iter<'a> {
+    dict: &'a Dictionary,
+    ent: *mut AVDictionaryEntry,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a CStr, &'a CStr);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            let c = 0;
+            self.ent = av_dict_get(
+                self.dict.0,
+                &c,
+                self.ent,
+                moonfire_ffmpeg_av_dict_ignore_suffix,
+            );
+            if self.ent.is_null() {
+                return None;
+            }
+            Some((
+                CStr::from_ptr((*self.ent).key),
+                CStr::from_ptr((*self.ent).value),
+            ))
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Dictionary {
+    type Item = (&'a CStr, &'a CStr);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl<'a> FromIterator<(&'a CStr, &'a CStr)> for Dictionary {
+    /// Panics if `av_dict_set` fails (e.g. `ENOMEM`); use `from_pairs` directly for a fallible
+    /// version.
+    fn from_iter<I: IntoIterator<Item = (&'a CStr, &'a CStr)>>(iter: I) -> Self {
+        Dictionary::from_pairs(iter).expect("av_dict_set failed")
+    }
 }
 
 impl Default for Dictionary {
@@ -287,27 +728,11 @@ impl Default for Dictionary {
 
 impl std::fmt::Display for Dictionary {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let mut ent = ptr::null_mut();
-        let mut first = true;
-        loop {
-            unsafe {
-                let c = 0;
-                ent = av_dict_get(self.0, &c, ent, moonfire_ffmpeg_av_dict_ignore_suffix);
-                if ent.is_null() {
-                    break;
-                }
-                if first {
-                    first = false;
-                } else {
-                    write!(f, ", ")?;
-                }
-                write!(
-                    f,
-                    "{}={}",
-                    CStr::from_ptr((*ent).key).to_string_lossy(),
-                    CStr::from_ptr((*ent).value).to_string_lossy()
-                )?;
+        for (i, (key, value)) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
             }
+            write!(f, "{}={}", key.to_string_lossy(), value.to_string_lossy())?;
         }
         Ok(())
     }
@@ -319,6 +744,27 @@ impl Drop for Dictionary {
     }
 }
 
+/// An `av_malloc`'d buffer, freed with `av_free` on drop unless handed off (e.g. to
+/// `avio_alloc_context`, which takes ownership of the buffer it's given) via `mem::forget`.
+pub(crate) struct Alloc(ptr::NonNull<u8>);
+
+impl Alloc {
+    pub(crate) fn new(len: usize) -> Result<Self, Error> {
+        let p = ptr::NonNull::new(unsafe { av_malloc(len) } as *mut u8).ok_or_else(Error::enomem)?;
+        Ok(Alloc(p))
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        self.0.as_ptr()
+    }
+}
+
+impl Drop for Alloc {
+    fn drop(&mut self) {
+        unsafe { av_free(self.0.as_ptr() as *mut libc::c_void) }
+    }
+}
+
 // Must match moonfire_ffmpeg_image_dimensions.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 #[repr(C)]
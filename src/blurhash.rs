@@ -0,0 +1,196 @@
+// Copyright (C) 2017-2020 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! [BlurHash](https://blurha.sh/) generation from a decoded frame: a short string that can be
+//! rendered as a blurry placeholder before the real thumbnail has loaded.
+
+use crate::avutil::{Error, ImageDimensions, PixelFormat, VideoFrame};
+use crate::swscale::Scaler;
+use std::f64::consts::PI;
+
+const BASE83_CHARS: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// The largest dimension (in pixels) the frame is downscaled to before transforming; BlurHash
+/// is meant to be cheap, and 32px of detail is already more than the algorithm can represent.
+const MAX_DIM: i32 = 32;
+
+/// Computes a BlurHash string for `frame` with the given number of DCT components on each axis
+/// (1..=9). More components capture more detail (and produce a longer string).
+pub fn encode(frame: &VideoFrame, x_components: usize, y_components: usize) -> Result<String, Error> {
+    assert!((1..=9).contains(&x_components));
+    assert!((1..=9).contains(&y_components));
+
+    let src_dims = frame.dims();
+    let (width, height) = downscaled_dims(src_dims.width, src_dims.height);
+    let dst_dims = ImageDimensions {
+        width,
+        height,
+        pix_fmt: PixelFormat::rgb24(),
+    };
+    let mut scaler = Scaler::new(src_dims, dst_dims)?;
+    let mut small = VideoFrame::owned(dst_dims)?;
+    scaler.scale(frame, &mut small);
+    Ok(encode_rgb24(&small, x_components, y_components))
+}
+
+/// Picks dimensions no larger than `MAX_DIM` on either axis, preserving aspect ratio.
+fn downscaled_dims(width: i32, height: i32) -> (i32, i32) {
+    if width <= MAX_DIM && height <= MAX_DIM {
+        return (width, height);
+    }
+    if width >= height {
+        (MAX_DIM, std::cmp::max(1, height * MAX_DIM / width))
+    } else {
+        (std::cmp::max(1, width * MAX_DIM / height), MAX_DIM)
+    }
+}
+
+fn srgb_to_linear(v: u8) -> f64 {
+    let v = f64::from(v) / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f64) -> i32 {
+    let v = v.clamp(0.0, 1.0);
+    let s = if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (s * 255.0 + 0.5) as i32
+}
+
+/// `x.signum() * |x|^exp`, used to quantize AC components without flipping their sign.
+fn sign_pow(value: f64, exp: f64) -> f64 {
+    value.signum() * value.abs().powf(exp)
+}
+
+fn encode_rgb24(frame: &VideoFrame, x_components: usize, y_components: usize) -> String {
+    let p = frame.plane(0);
+    let width = p.width as f64;
+    let height = p.height as f64;
+
+    // factors[j * x_components + i] is the (r, g, b) DCT coefficient for basis (i, j); factor
+    // (0, 0) is the DC (average color) term.
+    let mut factors = Vec::with_capacity(x_components * y_components);
+    for j in 0..y_components {
+        for i in 0..x_components {
+            let normalization = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let (mut r, mut g, mut b) = (0.0, 0.0, 0.0);
+            for y in 0..p.height {
+                let row = &p.data[y * p.linesize..];
+                let cos_y = (PI * j as f64 * y as f64 / height).cos();
+                for x in 0..p.width {
+                    let basis = normalization * (PI * i as f64 * x as f64 / width).cos() * cos_y;
+                    let px = x * 3;
+                    r += basis * srgb_to_linear(row[px]);
+                    g += basis * srgb_to_linear(row[px + 1]);
+                    b += basis * srgb_to_linear(row[px + 2]);
+                }
+            }
+            let scale = 1.0 / (width * height);
+            factors.push((r * scale, g * scale, b * scale));
+        }
+    }
+
+    let dc = factors[0];
+    let ac = &factors[1..];
+
+    let actual_maximum_value = ac
+        .iter()
+        .flat_map(|&(r, g, b)| [r, g, b])
+        .fold(0.0_f64, |acc, v| acc.max(v.abs()));
+    let quantised_maximum_value = if actual_maximum_value > 0.0 {
+        ((actual_maximum_value * 166.0 - 0.5).floor() as i32).clamp(0, 82)
+    } else {
+        0
+    };
+    let maximum_value = if ac.is_empty() {
+        1.0
+    } else {
+        (quantised_maximum_value + 1) as f64 / 166.0
+    };
+
+    let mut out = String::new();
+    let size_flag = (x_components - 1) + (y_components - 1) * 9;
+    encode83(size_flag as u32, 1, &mut out);
+    encode83(quantised_maximum_value as u32, 1, &mut out);
+    encode83(encode_dc(dc), 4, &mut out);
+    for &(r, g, b) in ac {
+        encode83(encode_ac(r, g, b, maximum_value), 2, &mut out);
+    }
+    out
+}
+
+fn encode_dc(dc: (f64, f64, f64)) -> u32 {
+    let (r, g, b) = dc;
+    ((linear_to_srgb(r) as u32) << 16) | ((linear_to_srgb(g) as u32) << 8) | (linear_to_srgb(b) as u32)
+}
+
+fn encode_ac(r: f64, g: f64, b: f64, maximum_value: f64) -> u32 {
+    let quantize = |v: f64| -> u32 { (sign_pow(v / maximum_value, 0.5) * 9.0 + 9.5).clamp(0.0, 18.0) as u32 };
+    quantize(r) * 19 * 19 + quantize(g) * 19 + quantize(b)
+}
+
+fn encode83(value: u32, length: usize, out: &mut String) {
+    for i in (0..length).rev() {
+        let digit = (value / 83u32.pow(i as u32)) % 83;
+        out.push(BASE83_CHARS[digit as usize] as char);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode83, encode_rgb24, linear_to_srgb, srgb_to_linear};
+    use crate::avutil::{ImageDimensions, PixelFormat, VideoFrame};
+
+    /// Every sRGB byte should round-trip exactly through `srgb_to_linear`/`linear_to_srgb`;
+    /// a double-rounding bug in `linear_to_srgb` used to skew about half of all values brighter
+    /// by one.
+    #[test]
+    fn srgb_roundtrip() {
+        for v in 0..=255u8 {
+            assert_eq!(linear_to_srgb(srgb_to_linear(v)), i32::from(v), "v={}", v);
+        }
+    }
+
+    #[test]
+    fn encode83_known_values() {
+        let mut out = String::new();
+        encode83(0, 1, &mut out);
+        assert_eq!(out, "0");
+
+        let mut out = String::new();
+        encode83(82, 1, &mut out);
+        assert_eq!(out, "~");
+
+        let mut out = String::new();
+        encode83(83, 2, &mut out);
+        assert_eq!(out, "10");
+    }
+
+    /// A 2x1 black/white image, hashed with 2x1 components, against an independently
+    /// hand-computed expected value. Catches sampling-offset bugs (e.g. a stray `+ 0.5` in the
+    /// DCT basis) that the purely numeric `srgb_roundtrip`/`encode83_known_values` tests above
+    /// can't, since those don't exercise `encode_rgb24`'s spatial sampling at all.
+    #[test]
+    fn encode_known_vector() {
+        crate::Ffmpeg::new();
+        let dims = ImageDimensions {
+            width: 2,
+            height: 1,
+            pix_fmt: PixelFormat::rgb24(),
+        };
+        let mut frame = VideoFrame::owned(dims).unwrap();
+        {
+            let mut p = frame.plane_mut(0);
+            p.data[0..6].copy_from_slice(&[0, 0, 0, 255, 255, 255]);
+        }
+        assert_eq!(encode_rgb24(&frame, 2, 1), "10Lqe9fQ");
+    }
+}
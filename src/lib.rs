@@ -14,6 +14,10 @@ pub mod avcodec;
 pub mod avformat;
 pub mod avutil;
 #[cfg(feature = "swscale")]
+pub mod blurhash;
+#[cfg(feature = "swresample")]
+pub mod resample;
+#[cfg(feature = "swscale")]
 pub mod swscale;
 
 pub use avutil::Error;
@@ -257,6 +261,12 @@ impl Ffmpeg {
                     swscale::moonfire_ffmpeg_compiled_libswscale_version,
                     swscale::swscale_version(),
                 ),
+                #[cfg(feature = "swresample")]
+                Library::new(
+                    "swresample",
+                    resample::moonfire_ffmpeg_compiled_libswresample_version,
+                    resample::swresample_version(),
+                ),
             ];
             let mut msg = format!(
                 "\ncompiled={:?} running={:?}",
@@ -0,0 +1,499 @@
+// Copyright (C) 2017-2020 Scott Lamb <slamb@slamb.org>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Audio resampling via libswresample, analogous to [`crate::swscale`] for video.
+
+use crate::avutil::{av_frame_alloc, av_frame_free, AVFrame, Error};
+use std::convert::TryFrom;
+use std::ptr;
+
+//#[link(name = "swresample")]
+extern "C" {
+    pub(crate) fn swresample_version() -> libc::c_int;
+
+    fn swr_alloc_set_opts2(
+        ps: *mut *mut SwrContext,
+        out_ch_layout: *const AVChannelLayout,
+        out_sample_fmt: libc::c_int,
+        out_sample_rate: libc::c_int,
+        in_ch_layout: *const AVChannelLayout,
+        in_sample_fmt: libc::c_int,
+        in_sample_rate: libc::c_int,
+        log_offset: libc::c_int,
+        log_ctx: *mut libc::c_void,
+    ) -> libc::c_int;
+    fn swr_init(s: *mut SwrContext) -> libc::c_int;
+    fn swr_free(s: *mut *mut SwrContext);
+    fn swr_convert(
+        s: *mut SwrContext,
+        out: *const *mut u8,
+        out_count: libc::c_int,
+        in_: *const *const u8,
+        in_count: libc::c_int,
+    ) -> libc::c_int;
+
+    /// Returns non-zero iff `sample_fmt` is planar (one data plane per channel, e.g. `fltp`)
+    /// rather than packed/interleaved (a single data plane, e.g. `s16`/`flt`).
+    fn av_sample_fmt_is_planar(sample_fmt: libc::c_int) -> libc::c_int;
+
+    fn av_audio_fifo_alloc(
+        sample_fmt: libc::c_int,
+        channels: libc::c_int,
+        nb_samples: libc::c_int,
+    ) -> *mut AVAudioFifo;
+    fn av_audio_fifo_free(af: *mut AVAudioFifo);
+    fn av_audio_fifo_write(
+        af: *mut AVAudioFifo,
+        data: *const *mut u8,
+        nb_samples: libc::c_int,
+    ) -> libc::c_int;
+    fn av_audio_fifo_read(
+        af: *mut AVAudioFifo,
+        data: *const *mut u8,
+        nb_samples: libc::c_int,
+    ) -> libc::c_int;
+    fn av_audio_fifo_size(af: *const AVAudioFifo) -> libc::c_int;
+
+    /// Returns the number of output samples `swr_convert` would produce for `in_samples` more
+    /// input samples, accounting for any samples already buffered inside `s`. Used to size the
+    /// output `AudioFrame` in `Resampler::convert_frame`/`flush_frame`.
+    fn swr_get_out_samples(s: *mut SwrContext, in_samples: libc::c_int) -> libc::c_int;
+}
+
+//#[link(name = "wrapper")]
+extern "C" {
+    pub(crate) static moonfire_ffmpeg_compiled_libswresample_version: libc::c_int;
+
+    static moonfire_ffmpeg_sample_fmt_s16: libc::c_int;
+    static moonfire_ffmpeg_sample_fmt_flt: libc::c_int;
+    static moonfire_ffmpeg_sample_fmt_fltp: libc::c_int;
+
+    fn moonfire_ffmpeg_channel_layout_from_mask(mask: u64) -> *mut AVChannelLayout;
+    fn moonfire_ffmpeg_channel_layout_free(l: *mut AVChannelLayout);
+    fn moonfire_ffmpeg_channel_layout_channels(l: *const AVChannelLayout) -> libc::c_int;
+
+    /// Sets `frame`'s format/channel count/sample rate/sample count (defaulting its channel
+    /// layout from the channel count) and allocates its sample buffer via
+    /// `av_frame_get_buffer`.
+    fn moonfire_ffmpeg_audio_frame_alloc(
+        frame: *mut AVFrame,
+        nb_samples: libc::c_int,
+        sample_fmt: libc::c_int,
+        channels: libc::c_int,
+        sample_rate: libc::c_int,
+    ) -> libc::c_int;
+    fn moonfire_ffmpeg_audio_frame_stuff(frame: *const AVFrame, stuff: *mut AudioFrameStuff);
+    fn moonfire_ffmpeg_audio_frame_set_nb_samples(frame: *mut AVFrame, nb_samples: libc::c_int);
+}
+
+// No ABI stability assumption here; use heap allocation/deallocation and accessors only.
+#[repr(C)]
+struct SwrContext {
+    _private: [u8; 0],
+}
+#[repr(C)]
+struct AVAudioFifo {
+    _private: [u8; 0],
+}
+#[repr(C)]
+struct AVChannelLayout {
+    _private: [u8; 0],
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct SampleFormat(libc::c_int);
+
+impl SampleFormat {
+    pub fn s16() -> Self {
+        SampleFormat(unsafe { moonfire_ffmpeg_sample_fmt_s16 })
+    }
+    pub fn flt() -> Self {
+        SampleFormat(unsafe { moonfire_ffmpeg_sample_fmt_flt })
+    }
+    pub fn fltp() -> Self {
+        SampleFormat(unsafe { moonfire_ffmpeg_sample_fmt_fltp })
+    }
+
+    /// Returns true iff samples in this format are stored one plane per channel (e.g. `fltp`)
+    /// rather than packed into a single interleaved plane (e.g. `s16`/`flt`).
+    pub fn is_planar(self) -> bool {
+        (unsafe { av_sample_fmt_is_planar(self.0) }) != 0
+    }
+}
+
+/// An owned `AVChannelLayout`, e.g. mono/stereo/5.1, built from a classic `AV_CH_LAYOUT_*` mask.
+pub struct ChannelLayout(ptr::NonNull<AVChannelLayout>);
+
+impl ChannelLayout {
+    pub fn from_mask(mask: u64) -> Result<Self, Error> {
+        let l = ptr::NonNull::new(unsafe { moonfire_ffmpeg_channel_layout_from_mask(mask) })
+            .ok_or_else(Error::enomem)?;
+        Ok(ChannelLayout(l))
+    }
+
+    pub fn channels(&self) -> usize {
+        (unsafe { moonfire_ffmpeg_channel_layout_channels(self.0.as_ptr()) }) as usize
+    }
+}
+
+impl Drop for ChannelLayout {
+    fn drop(&mut self) {
+        unsafe { moonfire_ffmpeg_channel_layout_free(self.0.as_ptr()) }
+    }
+}
+
+/// The sample format/layout/rate of an audio stream, as consumed by [`Resampler::new`].
+pub struct AudioSpec<'a> {
+    pub sample_fmt: SampleFormat,
+    pub channel_layout: &'a ChannelLayout,
+    pub sample_rate: libc::c_int,
+}
+
+pub struct Resampler {
+    ctx: ptr::NonNull<SwrContext>,
+    dst_sample_fmt: SampleFormat,
+    dst_channels: usize,
+    dst_sample_rate: libc::c_int,
+}
+
+impl Resampler {
+    pub fn new(src: AudioSpec, dst: AudioSpec) -> Result<Self, Error> {
+        let mut ctx = ptr::null_mut();
+        Error::wrap(unsafe {
+            swr_alloc_set_opts2(
+                &mut ctx,
+                dst.channel_layout.0.as_ptr(),
+                dst.sample_fmt.0,
+                dst.sample_rate,
+                src.channel_layout.0.as_ptr(),
+                src.sample_fmt.0,
+                src.sample_rate,
+                0,
+                ptr::null_mut(),
+            )
+        })?;
+        let ctx = ptr::NonNull::new(ctx).ok_or_else(Error::enomem)?;
+        Error::wrap(unsafe { swr_init(ctx.as_ptr()) })?;
+        Ok(Resampler {
+            ctx,
+            dst_sample_fmt: dst.sample_fmt,
+            dst_channels: dst.channel_layout.channels(),
+            dst_sample_rate: dst.sample_rate,
+        })
+    }
+
+    /// Converts all of `input`'s samples at once into a freshly allocated `AudioFrame`, unlike
+    /// `convert`/`flush`, which write into caller-supplied buffers. Handles the buffering/latency
+    /// a fractional rate conversion introduces the same way `convert`/`flush` do: callers that
+    /// need the last few buffered samples out should follow up with `flush_frame`.
+    pub fn convert_frame(&mut self, input: &AudioFrame) -> Result<AudioFrame, Error> {
+        self.convert_frame_raw(Some(input))
+    }
+
+    /// Like `convert_frame`, but with no new input: drains samples buffered inside the
+    /// resampler (e.g. from a fractional rate conversion). Call repeatedly until the returned
+    /// frame's `nb_samples()` is `0`.
+    pub fn flush_frame(&mut self) -> Result<AudioFrame, Error> {
+        self.convert_frame_raw(None)
+    }
+
+    fn convert_frame_raw(&mut self, input: Option<&AudioFrame>) -> Result<AudioFrame, Error> {
+        let in_samples = input.map_or(0, AudioFrame::nb_samples);
+        let in_samples_c = libc::c_int::try_from(in_samples).unwrap();
+        let out_capacity =
+            Error::wrap(unsafe { swr_get_out_samples(self.ctx.as_ptr(), in_samples_c) })?;
+        // `av_frame_get_buffer` rejects a 0-sample audio frame, so allocate at least one sample;
+        // `set_nb_samples` below trims the frame to what `swr_convert` actually wrote.
+        let mut output = AudioFrame::new(
+            self.dst_sample_fmt,
+            self.dst_channels,
+            self.dst_sample_rate,
+            usize::try_from(out_capacity.max(1)).unwrap(),
+        )?;
+        let in_ptrs: Option<Vec<*const u8>> = input.map(|f| {
+            (0..sample_planes(f.sample_fmt(), f.channels()))
+                .map(|p| f.plane(p).as_ptr())
+                .collect()
+        });
+        let out_planes = sample_planes(output.sample_fmt(), output.channels());
+        let mut out_ptrs: Vec<*mut u8> = (0..out_planes)
+            .map(|p| output.plane_mut(p).as_mut_ptr())
+            .collect();
+        let n = Error::wrap(unsafe {
+            swr_convert(
+                self.ctx.as_ptr(),
+                out_ptrs.as_mut_ptr(),
+                out_capacity,
+                in_ptrs.as_ref().map_or(ptr::null(), |v| v.as_ptr()),
+                in_samples_c,
+            )
+        })?;
+        output.set_nb_samples(usize::try_from(n).unwrap());
+        Ok(output)
+    }
+
+    /// Converts `in_samples` samples of `input` (one slice per plane; a single slice for
+    /// interleaved/packed formats) into `output`, returning the number of samples written.
+    /// `output` must have room for at least `out_capacity` samples per plane.
+    pub fn convert(
+        &mut self,
+        input: &[&[u8]],
+        in_samples: usize,
+        output: &mut [&mut [u8]],
+        out_capacity: usize,
+    ) -> Result<usize, Error> {
+        let in_ptrs: Vec<*const u8> = input.iter().map(|p| p.as_ptr()).collect();
+        self.convert_raw(in_ptrs.as_ptr(), in_samples, output, out_capacity)
+    }
+
+    /// Flushes any samples buffered inside the resampler (e.g. due to a fractional rate
+    /// conversion). Call repeatedly until it returns `Ok(0)`.
+    pub fn flush(
+        &mut self,
+        output: &mut [&mut [u8]],
+        out_capacity: usize,
+    ) -> Result<usize, Error> {
+        self.convert_raw(ptr::null(), 0, output, out_capacity)
+    }
+
+    fn convert_raw(
+        &mut self,
+        in_ptrs: *const *const u8,
+        in_samples: usize,
+        output: &mut [&mut [u8]],
+        out_capacity: usize,
+    ) -> Result<usize, Error> {
+        let mut out_ptrs: Vec<*mut u8> = output.iter_mut().map(|p| p.as_mut_ptr()).collect();
+        let n = Error::wrap(unsafe {
+            swr_convert(
+                self.ctx.as_ptr(),
+                out_ptrs.as_mut_ptr(),
+                libc::c_int::try_from(out_capacity).unwrap(),
+                in_ptrs,
+                libc::c_int::try_from(in_samples).unwrap(),
+            )
+        })?;
+        Ok(usize::try_from(n).unwrap())
+    }
+}
+
+/// The number of `AudioFrame`/`swr_convert` data planes `sample_fmt` uses for `channels`
+/// channels: one per channel if planar, or a single interleaved plane otherwise.
+fn sample_planes(sample_fmt: SampleFormat, channels: usize) -> usize {
+    if sample_fmt.is_planar() {
+        channels.max(1)
+    } else {
+        1
+    }
+}
+
+impl Drop for Resampler {
+    fn drop(&mut self) {
+        let mut ctx = self.ctx.as_ptr();
+        unsafe { swr_free(&mut ctx) };
+    }
+}
+
+/// A FIFO of audio samples, used to accumulate variable-size resampled output into the
+/// fixed-size frames many encoders require.
+pub struct AudioFifo {
+    fifo: ptr::NonNull<AVAudioFifo>,
+    planes: usize,
+}
+
+impl AudioFifo {
+    pub fn new(
+        sample_fmt: SampleFormat,
+        channels: usize,
+        initial_capacity_samples: usize,
+    ) -> Result<Self, Error> {
+        let fifo = ptr::NonNull::new(unsafe {
+            av_audio_fifo_alloc(
+                sample_fmt.0,
+                libc::c_int::try_from(channels).unwrap(),
+                libc::c_int::try_from(initial_capacity_samples).unwrap(),
+            )
+        })
+        .ok_or_else(Error::enomem)?;
+        Ok(AudioFifo {
+            fifo,
+            planes: sample_planes(sample_fmt, channels),
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        (unsafe { av_audio_fifo_size(self.fifo.as_ptr()) }) as usize
+    }
+
+    /// Pushes samples into the FIFO; `data` must have one slice per channel (or a single slice
+    /// for interleaved data).
+    pub fn write(&mut self, data: &[&[u8]], nb_samples: usize) -> Result<usize, Error> {
+        assert_eq!(data.len(), self.planes);
+        let ptrs: Vec<*mut u8> = data.iter().map(|p| p.as_ptr() as *mut u8).collect();
+        let n = Error::wrap(unsafe {
+            av_audio_fifo_write(
+                self.fifo.as_ptr(),
+                ptrs.as_ptr(),
+                libc::c_int::try_from(nb_samples).unwrap(),
+            )
+        })?;
+        Ok(usize::try_from(n).unwrap())
+    }
+
+    /// Pulls exactly `frame_size` samples out of the FIFO; callers should check `size()` first.
+    pub fn read(&mut self, data: &mut [&mut [u8]], frame_size: usize) -> Result<usize, Error> {
+        assert_eq!(data.len(), self.planes);
+        let ptrs: Vec<*mut u8> = data.iter_mut().map(|p| p.as_mut_ptr()).collect();
+        let n = Error::wrap(unsafe {
+            av_audio_fifo_read(
+                self.fifo.as_ptr(),
+                ptrs.as_ptr(),
+                libc::c_int::try_from(frame_size).unwrap(),
+            )
+        })?;
+        Ok(usize::try_from(n).unwrap())
+    }
+}
+
+impl Drop for AudioFifo {
+    fn drop(&mut self) {
+        unsafe { av_audio_fifo_free(self.fifo.as_ptr()) }
+    }
+}
+
+/// Matches `moonfire_ffmpeg_audio_frame_stuff`'s output struct.
+#[repr(C)]
+struct AudioFrameStuff {
+    sample_fmt: libc::c_int,
+    sample_rate: libc::c_int,
+    channels: libc::c_int,
+    nb_samples: libc::c_int,
+    data: *const *mut u8,
+    linesizes: *const libc::c_int,
+}
+
+/// A decoded/resampled audio frame, analogous to [`crate::avutil::VideoFrame`] for video:
+/// wraps an `AVFrame`, reusing the same ABI-safe "populate a plain-data struct via a wrapper
+/// shim" pattern rather than assuming anything about `AVFrame`'s own layout.
+pub struct AudioFrame {
+    frame: ptr::NonNull<AVFrame>,
+    stuff: AudioFrameStuff,
+}
+
+impl AudioFrame {
+    /// Allocates a new `AudioFrame` with `nb_samples` samples of storage in the given format.
+    pub fn new(
+        sample_fmt: SampleFormat,
+        channels: usize,
+        sample_rate: libc::c_int,
+        nb_samples: usize,
+    ) -> Result<Self, Error> {
+        let frame = ptr::NonNull::new(unsafe { av_frame_alloc() }).ok_or_else(Error::enomem)?;
+        Error::wrap(unsafe {
+            moonfire_ffmpeg_audio_frame_alloc(
+                frame.as_ptr(),
+                libc::c_int::try_from(nb_samples).unwrap(),
+                sample_fmt.0,
+                libc::c_int::try_from(channels).unwrap(),
+                sample_rate,
+            )
+        })?;
+        let mut stuff = AudioFrameStuff {
+            sample_fmt: 0,
+            sample_rate: 0,
+            channels: 0,
+            nb_samples: 0,
+            data: ptr::null(),
+            linesizes: ptr::null(),
+        };
+        unsafe { moonfire_ffmpeg_audio_frame_stuff(frame.as_ptr(), &mut stuff) };
+        Ok(AudioFrame { frame, stuff })
+    }
+
+    pub fn sample_fmt(&self) -> SampleFormat {
+        SampleFormat(self.stuff.sample_fmt)
+    }
+    pub fn sample_rate(&self) -> libc::c_int {
+        self.stuff.sample_rate
+    }
+    pub fn channels(&self) -> usize {
+        self.stuff.channels as usize
+    }
+    pub fn nb_samples(&self) -> usize {
+        self.stuff.nb_samples as usize
+    }
+
+    /// Returns the sample data for `plane`: one plane per channel for planar formats like
+    /// `fltp`, or a single interleaved plane 0 for packed formats like `s16`/`flt`.
+    pub fn plane(&self, plane: usize) -> &[u8] {
+        let off = isize::try_from(plane).unwrap();
+        let d = unsafe { *self.stuff.data.offset(off) };
+        let l = unsafe { *self.stuff.linesizes.offset(off) };
+        assert!(!d.is_null());
+        assert!(l > 0);
+        unsafe { std::slice::from_raw_parts(d, l as usize) }
+    }
+
+    /// Like `plane`, but allows writing into the plane's backing storage.
+    pub fn plane_mut(&mut self, plane: usize) -> &mut [u8] {
+        let off = isize::try_from(plane).unwrap();
+        let d = unsafe { *self.stuff.data.offset(off) };
+        let l = unsafe { *self.stuff.linesizes.offset(off) };
+        assert!(!d.is_null());
+        assert!(l > 0);
+        unsafe { std::slice::from_raw_parts_mut(d, l as usize) }
+    }
+
+    /// Updates `nb_samples` to reflect that only `n` of this frame's allocated samples are
+    /// actually valid, e.g. after `swr_convert` wrote fewer samples than the buffer's capacity.
+    fn set_nb_samples(&mut self, n: usize) {
+        unsafe {
+            moonfire_ffmpeg_audio_frame_set_nb_samples(
+                self.frame.as_ptr(),
+                libc::c_int::try_from(n).unwrap(),
+            );
+            moonfire_ffmpeg_audio_frame_stuff(self.frame.as_ptr(), &mut self.stuff);
+        }
+    }
+}
+
+impl Drop for AudioFrame {
+    fn drop(&mut self) {
+        let mut frame = self.frame.as_ptr();
+        unsafe { av_frame_free(&mut frame) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AudioFrame, AudioSpec, ChannelLayout, Resampler, SampleFormat};
+
+    const AV_CH_LAYOUT_STEREO: u64 = 0x3; // AV_CH_FRONT_LEFT | AV_CH_FRONT_RIGHT
+
+    /// `convert_frame`'s plane-gathering must special-case packed (non-planar) destination
+    /// formats like `s16`: a stereo `s16` frame has only `data[0]` populated, not one plane per
+    /// channel as with `fltp`.
+    #[test]
+    fn convert_frame_packed_stereo() {
+        crate::Ffmpeg::new();
+        let src_layout = ChannelLayout::from_mask(AV_CH_LAYOUT_STEREO).unwrap();
+        let dst_layout = ChannelLayout::from_mask(AV_CH_LAYOUT_STEREO).unwrap();
+        let mut resampler = Resampler::new(
+            AudioSpec {
+                sample_fmt: SampleFormat::fltp(),
+                channel_layout: &src_layout,
+                sample_rate: 44100,
+            },
+            AudioSpec {
+                sample_fmt: SampleFormat::s16(),
+                channel_layout: &dst_layout,
+                sample_rate: 44100,
+            },
+        )
+        .unwrap();
+        let input = AudioFrame::new(SampleFormat::fltp(), 2, 44100, 1024).unwrap();
+        let output = resampler.convert_frame(&input).unwrap();
+        assert_eq!(output.sample_fmt(), SampleFormat::s16());
+    }
+}